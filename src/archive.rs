@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use std::io::Read;
+
+/// Recognized archive/compression formats, detected from a (possibly virtual,
+/// `!`-delimited) path's trailing extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar,
+    TarGzip,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+    Plain,
+}
+
+impl Format {
+    pub fn detect(name: &str) -> Format {
+        let lower = name.to_ascii_lowercase();
+        if lower.ends_with(".tar.gz") || lower.ends_with(".tgz") {
+            Format::TarGzip
+        } else if lower.ends_with(".zip") {
+            Format::Zip
+        } else if lower.ends_with(".tar") {
+            Format::Tar
+        } else if lower.ends_with(".gz") {
+            Format::Gzip
+        } else if lower.ends_with(".bz2") {
+            Format::Bzip2
+        } else if lower.ends_with(".xz") {
+            Format::Xz
+        } else if lower.ends_with(".zst") {
+            Format::Zstd
+        } else {
+            Format::Plain
+        }
+    }
+
+    pub fn is_archive(self) -> bool {
+        !matches!(self, Format::Plain)
+    }
+
+    /// The name with this format's compression suffix stripped, used as the virtual path
+    /// for the stream produced by [`decode_stream`]. Container formats have no single
+    /// decoded name, since they expand into many entries.
+    pub fn strip_suffix(self, name: &str) -> String {
+        // Match the suffix case-insensitively, as `detect` does.
+        let strip = |suffix: &str| {
+            if name.to_ascii_lowercase().ends_with(suffix) {
+                name[..name.len() - suffix.len()].to_string()
+            } else {
+                name.to_string()
+            }
+        };
+        match self {
+            Format::Gzip => strip(".gz"),
+            Format::Bzip2 => strip(".bz2"),
+            Format::Xz => strip(".xz"),
+            Format::Zstd => strip(".zst"),
+            Format::Zip | Format::Tar | Format::TarGzip | Format::Plain => name.to_string(),
+        }
+    }
+
+    /// Only meaningful for the single-stream compressors; `Zip`/`Tar`/`TarGzip` are
+    /// containers with multiple named entries and are enumerated by the caller instead.
+    pub fn decode_stream<'a>(self, data: Box<dyn Read + 'a>) -> Result<Box<dyn Read + 'a>> {
+        match self {
+            Format::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(data))),
+            Format::Bzip2 => Ok(Box::new(bzip2::read::BzDecoder::new(data))),
+            Format::Xz => Ok(Box::new(xz2::read::XzDecoder::new(data))),
+            Format::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(data)?)),
+            Format::Plain => Ok(data),
+            Format::Zip | Format::Tar | Format::TarGzip => Err(anyhow!(
+                "{self:?} is a container format with named entries, not a byte stream"
+            )),
+        }
+    }
+}
+
+/// Tracks how many bytes have been expanded out of a single top-level archive, so a
+/// maliciously (or accidentally) nested archive can't exhaust memory. Shared across every
+/// level of recursion reached from one top-level file.
+pub struct ExpansionBudget {
+    max_depth: usize,
+    max_bytes: u64,
+    used_bytes: std::sync::atomic::AtomicU64,
+}
+
+impl ExpansionBudget {
+    pub fn new(max_depth: usize, max_bytes: u64) -> Self {
+        Self {
+            max_depth,
+            max_bytes,
+            used_bytes: Default::default(),
+        }
+    }
+
+    pub fn check_depth(&self, depth: usize) -> Result<()> {
+        if depth > self.max_depth {
+            Err(anyhow!(
+                "max archive depth ({}) exceeded at depth {depth}",
+                self.max_depth
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Account for `bytes` more having been read out of an archive, failing once the
+    /// cumulative total for this top-level archive exceeds `max_bytes`.
+    pub fn charge(&self, bytes: u64) -> Result<()> {
+        let used = self
+            .used_bytes
+            .fetch_add(bytes, std::sync::atomic::Ordering::Relaxed)
+            + bytes;
+        if used > self.max_bytes {
+            Err(anyhow!(
+                "expanded archive size exceeds --max-expanded-bytes ({used} > {})",
+                self.max_bytes
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Read all of `data` into `buf`, charging every chunk against `budget` as it's read so a
+/// decompression bomb is caught mid-read instead of after fully inflating into memory.
+pub fn read_capped<R: Read>(mut data: R, budget: &ExpansionBudget, buf: &mut Vec<u8>) -> Result<()> {
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let n = data.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        budget.charge(n as u64)?;
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(())
+}
+
+/// A reader that charges every byte it yields against an [`ExpansionBudget`] as it streams
+/// by, so an entry can keep being decoded/searched lazily instead of being buffered into
+/// memory first just to cap a decompression bomb.
+pub struct BudgetedReader<'a, R> {
+    inner: R,
+    budget: &'a ExpansionBudget,
+}
+
+impl<'a, R: Read> BudgetedReader<'a, R> {
+    pub fn new(inner: R, budget: &'a ExpansionBudget) -> Self {
+        Self { inner, budget }
+    }
+}
+
+impl<'a, R: Read> Read for BudgetedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.budget
+            .charge(n as u64)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn detect_recognizes_extensions() {
+        assert_eq!(Format::TarGzip, Format::detect("a.tar.gz"));
+        assert_eq!(Format::TarGzip, Format::detect("a.tgz"));
+        assert_eq!(Format::Zip, Format::detect("a.zip"));
+        assert_eq!(Format::Gzip, Format::detect("a.txt.gz"));
+        assert_eq!(Format::Plain, Format::detect("a.txt"));
+    }
+
+    #[test]
+    fn strip_suffix_only_strips_single_stream_compressors() {
+        assert_eq!("a.txt", Format::Gzip.strip_suffix("a.txt.gz"));
+        assert_eq!("a.zip", Format::Zip.strip_suffix("a.zip"));
+    }
+
+    #[test]
+    fn charge_fails_once_max_bytes_exceeded() {
+        let budget = ExpansionBudget::new(8, 10);
+        assert!(budget.charge(5).is_ok());
+        assert!(budget.charge(5).is_ok());
+        assert!(budget.charge(1).is_err());
+    }
+
+    #[test]
+    fn check_depth_fails_past_max_depth() {
+        let budget = ExpansionBudget::new(2, u64::MAX);
+        assert!(budget.check_depth(2).is_ok());
+        assert!(budget.check_depth(3).is_err());
+    }
+
+    #[test]
+    fn budgeted_reader_charges_bytes_read() {
+        let budget = ExpansionBudget::new(8, 3);
+        let mut reader = BudgetedReader::new(Cursor::new(b"abcd".to_vec()), &budget);
+        let mut buf = [0u8; 4];
+        assert!(reader.read(&mut buf).is_err());
+    }
+}
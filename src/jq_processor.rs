@@ -54,10 +54,14 @@ impl TextProcessor for ZipDirAnalyzer<JqProcessor> {
                     .processor
                     .filter
                     .run((Ctx::new([], &inputs), Val::from(value.clone())));
-                for result in results {
+                for (result_number, result) in results.enumerate() {
                     match result {
                         Result::Ok(json_val) => {
-                            if self.report(path, &mut core::iter::once(json_val.to_string()))? {
+                            if self.report(
+                                path,
+                                result_number as u64,
+                                &mut core::iter::once(json_val.to_string()),
+                            )? {
                                 return Ok(true);
                             }
                         }
@@ -1,21 +1,31 @@
 use anyhow::{Ok, Result};
 use clap::*;
+use crossbeam_channel::{Receiver, Sender};
+use ignore::{WalkBuilder, WalkState};
 use indicatif::{ProgressBar, ProgressStyle};
 use regex::Regex;
 use std::{
     fs::{self, File},
-    io::{self, Read},
-    path::Path,
-    sync::{atomic::AtomicU64, Arc, Mutex},
-    thread,
-    time::Duration,
+    io::{self, Cursor, Read, Seek},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
 };
-use threadpool::ThreadPool;
 
-use crate::{jq_processor::JqProcessor, regex_processor::RegexProcessor};
+use crate::{
+    archive::{BudgetedReader, ExpansionBudget, Format},
+    jq_processor::JqProcessor,
+    regex_processor::RegexProcessor,
+    reporter::{Reporter, SortMode},
+};
 
+mod archive;
 mod jq_processor;
 mod regex_processor;
+mod reporter;
 mod shared_iterator;
 
 fn main() -> Result<()> {
@@ -48,6 +58,8 @@ enum Output {
 /// Search directory for files matching the file_pat that include the pattern. The contents of zip files are also searched.
 ///
 /// The progress is reported as files processed from the filesystem, not files within the zips. X zips each of Y files will report X operations, not X*Y operations.
+///
+/// The directory walk honors `.gitignore`, `.ignore`, and global gitignore rules by default, and skips hidden files, the same way `rg`/`fd` do.
 #[derive(Parser, Debug, Default, Clone)]
 #[command(version, about)]
 pub struct Args {
@@ -95,6 +107,12 @@ pub struct Args {
     #[arg(long, default_value_t = 5)]
     max_errors: usize,
 
+    /// Text encoding to assume for files with no byte-order mark (a BOM-prefixed
+    /// UTF-16LE/UTF-16BE/UTF-8 file is always auto-detected regardless of this setting).
+    /// Accepts any WHATWG encoding label, e.g. "utf-8", "windows-1252", "utf-16".
+    #[arg(long, default_value = "windows-1252")]
+    encoding: String,
+
     /// Use jaq (similar to jq) to query JSON files instead of regex.
     #[arg(long, default_value_t = false)]
     jq: bool,
@@ -102,11 +120,59 @@ pub struct Args {
     /// How many lines after matching line should be reported.
     #[arg(long, short = 'A', default_value_t = 0)]
     after: u32,
+
+    /// How many lines before the matching line should be reported.
+    #[arg(long, short = 'B', default_value_t = 0)]
+    before: u32,
+
+    /// Do not respect .gitignore/.ignore files. Hidden files are still skipped unless
+    /// `--hidden` is also given.
+    #[arg(long, default_value_t = false)]
+    no_ignore: bool,
+
+    /// Include hidden files and directories in the walk.
+    #[arg(long, default_value_t = false)]
+    hidden: bool,
+
+    /// Follow symbolic links while walking the directory.
+    #[arg(long, default_value_t = false)]
+    follow: bool,
+
+    /// Max nesting depth when descending into archives within archives (a zip of a zip
+    /// of a zip, ...), to guard against decompression bombs.
+    #[arg(long, default_value_t = 8)]
+    max_archive_depth: usize,
+
+    /// Max total bytes a single top-level archive may expand to across all nested
+    /// archives, to guard against decompression bombs.
+    #[arg(long, default_value_t = 4 * 1024 * 1024 * 1024)]
+    max_expanded_bytes: u64,
+
+    /// Always buffer every match and print them sorted by path, however long that takes.
+    #[arg(long, conflicts_with = "no_sort")]
+    sort: bool,
+
+    /// Never buffer for sorting; print each match as soon as it's found, unsorted.
+    #[arg(long)]
+    no_sort: bool,
+}
+
+/// A unit of work handed to a worker thread, or the sentinel that tells it to stop.
+enum WorkItem {
+    Path(PathBuf),
+    Stop,
 }
 
 struct ZipDirAnalyzer<TP> {
-    pool: ThreadPool,
-    stdout_lock: Mutex<()>,
+    work_tx: Sender<WorkItem>,
+    work_rx: Receiver<WorkItem>,
+    /// How many scheduled paths are queued or being processed right now.
+    in_flight: AtomicU64,
+    /// Set once the directory walk has discovered everything it's going to discover.
+    walk_done: AtomicBool,
+    done_lock: Mutex<()>,
+    done_cv: Condvar,
+    reporter: Reporter,
     ops_complete: AtomicU64,
     processor: TP,
     file_regex: Regex,
@@ -125,33 +191,92 @@ where
         )?);
 
         let this = Arc::new(self);
-        let c = this.clone();
-        this.pool.execute(move || {
-            if c.args.directory == "-" {
-                for line in io::stdin().lines() {
-                    c.schedule_walk_path(Path::new(line.unwrap().as_str()));
-                }
-            } else {
-                c.schedule_walk_path(Path::new(c.args.directory.as_str()));
+
+        let workers: Vec<JoinHandle<()>> = (0..this.args.parallel)
+            .map(|_| {
+                let worker = this.clone();
+                thread::spawn(move || worker.worker_loop())
+            })
+            .collect();
+
+        if this.args.directory == "-" {
+            for line in io::stdin().lines() {
+                this.walk_root(line.unwrap().as_str());
             }
-        });
+        } else {
+            this.walk_root(this.args.directory.as_str());
+        }
+
+        // The walk is done discovering files. The wait below re-checks in_flight before it
+        // blocks, so a queue that workers already drained to zero is not missed.
+        this.walk_done.store(true, Ordering::Relaxed);
+        {
+            let _guard = this.done_lock.lock().unwrap();
+            this.done_cv.notify_all();
+        }
 
-        let mut scheduled = 1;
-        let mut complete = 0;
-        // wait for all processing to complete
-        while scheduled > complete {
-            thread::sleep(Duration::from_millis(50));
-            complete = this.ops_complete.load(std::sync::atomic::Ordering::Relaxed);
-            scheduled = (this.pool.active_count() + this.pool.queued_count()) as u64 + complete;
-            this.progress.set_length(scheduled);
-            this.progress.set_position(complete);
+        // Block until every scheduled path has finished processing. No polling: workers
+        // notify this condvar whenever the in-flight count reaches zero.
+        {
+            let mut guard = this.done_lock.lock().unwrap();
+            while !(this.walk_done.load(Ordering::Relaxed) && this.in_flight.load(Ordering::Relaxed) == 0)
+            {
+                guard = this.done_cv.wait(guard).unwrap();
+            }
+        }
+
+        for _ in &workers {
+            // workers are all still waiting on work_rx.recv(), so this can't fail
+            let _ = this.work_tx.send(WorkItem::Stop);
+        }
+        for worker in workers {
+            worker.join().map_err(|_| anyhow::anyhow!("worker thread panicked"))?;
+        }
+
+        let complete = this.ops_complete.load(Ordering::Relaxed);
+        this.progress.println(format!("Complete {complete} of {complete}"));
+
+        // Every worker has joined, so this is the only remaining reference and
+        // `try_unwrap` can't fail.
+        match Arc::try_unwrap(this) {
+            Result::Ok(me) => me.reporter.finish()?,
+            Err(_) => unreachable!("worker threads outlived run()'s completion check"),
         }
-        this.progress
-            .println(format!("Complete {complete} of {scheduled}"));
 
         Ok(())
     }
 
+    /// Pull paths off the work queue and process them until told to `Stop`.
+    fn worker_loop(self: Arc<Self>) {
+        while let std::result::Result::Ok(item) = self.work_rx.recv() {
+            let path = match item {
+                WorkItem::Stop => break,
+                WorkItem::Path(path) => path,
+            };
+            // A bad file (e.g. a line-read error .unwrap()'d during -A lookahead) can panic
+            // partway through walk_path. Catch it here so one bad file can't leave in_flight
+            // stuck above zero and hang run()'s completion wait forever; the old ThreadPool
+            // this replaced had the same safety net built in.
+            if let Err(panic) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                self.walk_path(&path)
+            })) {
+                if !self.args.quiet {
+                    self.progress.println(format!(
+                        "WARN: {path:?} panicked during processing: {}",
+                        panic_message(&panic)
+                    ));
+                }
+            }
+            self.progress.inc(1);
+            if self.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+                // We were the last in-flight item; wake up run() so it can check whether
+                // the walk has also finished discovering files.
+                let _guard = self.done_lock.lock().unwrap();
+                self.done_cv.notify_all();
+            }
+        }
+    }
+
     fn search_file<T: Read>(&self, path: &str, data: T) -> Result<()> {
         if self.file_regex.is_match(path) {
             self.progress.set_message(format!("processing: {path}"));
@@ -162,86 +287,141 @@ where
         Ok(())
     }
 
-    /// all reporting
-    fn report(&self, file: &str, lines: &mut dyn Iterator<Item = String>) -> Result<bool> {
-        let _io = self.stdout_lock.lock();
-        match self.args.output {
+    /// All reporting. `lines` must already be bounded to exactly the before/current/after
+    /// lines the caller wants printed; report() doesn't re-trim it, since it has no way to
+    /// tell a short `before` prefix (e.g. a match near the start of a file) from room left
+    /// over for more `after` lines.
+    fn report(
+        &self,
+        file: &str,
+        line_number: u64,
+        lines: &mut dyn Iterator<Item = String>,
+    ) -> Result<bool> {
+        let (text, done) = match self.args.output {
             Output::File => {
                 let file = file.split(&self.args.zip_delimiter).next().unwrap_or(file);
-                println!("{file}");
-                Ok(true)
-            }
-            Output::Entry => {
-                println!("{file}");
-                Ok(true)
+                (file.to_string(), true)
             }
+            Output::Entry => (file.to_string(), true),
             Output::All => {
                 let delimiter = &self.args.delimiter;
                 let line_delimiter = &self.args.line_delimiter;
                 let s = lines
-                    .take(1 + self.args.after as usize)
                     .map(|line| format!("{file}{delimiter}{line}"))
                     .fold(String::new(), |a, b| a + line_delimiter + &b);
-                println!("{s}");
-                Ok(false)
+                (s, false)
             }
             Output::Pattern => {
                 let line_delimiter = &self.args.line_delimiter;
                 let s = lines
-                    .take(1 + self.args.after as usize)
                     .map(|line| line.to_string())
                     .fold(String::new(), |a, b| a + line_delimiter + &b);
-                println!("{s}");
-                Ok(false)
+                (s, false)
             }
-        }
+        };
+        self.reporter.send(file, line_number, text);
+        Ok(done)
     }
 
     pub fn new(args: Args, processor: TP) -> Result<ZipDirAnalyzer<TP>>
     where
         TP: Send + 'static,
     {
+        let sort_mode = if args.sort {
+            SortMode::Sort
+        } else if args.no_sort {
+            SortMode::NoSort
+        } else {
+            SortMode::Auto
+        };
+        let (work_tx, work_rx) = crossbeam_channel::unbounded();
         Ok(ZipDirAnalyzer {
-            pool: ThreadPool::with_name("worker".to_string(), args.parallel),
-            stdout_lock: Mutex::new(()),
+            work_tx,
+            work_rx,
+            in_flight: Default::default(),
+            walk_done: AtomicBool::new(false),
+            done_lock: Mutex::new(()),
+            done_cv: Condvar::new(),
+            reporter: Reporter::spawn(sort_mode),
             ops_complete: Default::default(),
             processor,
             file_regex: Regex::new(&args.file_pat)?,
             args,
-            progress: ProgressBar::new(100),
+            progress: ProgressBar::new(0),
         })
     }
 
-    /// path is a directory.  Process each entry in a separate thread.
-    fn walk_dir(self: &Arc<Self>, path: &Path) -> Result<()> {
-        for entry in std::fs::read_dir(path)? {
-            self.schedule_walk_path(entry?.path().as_path());
-        }
-        Ok(())
+    /// Walk `root` with `ignore::WalkParallel`, honoring .gitignore/.ignore/hidden-file rules
+    /// (or not, per `--no-ignore`/`--hidden`/`--follow`), and schedule each discovered file for
+    /// processing. Directories are never scheduled; the walker descends into them itself.
+    fn walk_root(self: &Arc<Self>, root: &str) {
+        let mut builder = WalkBuilder::new(root);
+        builder
+            .hidden(!self.args.hidden)
+            .ignore(!self.args.no_ignore)
+            .git_ignore(!self.args.no_ignore)
+            .git_global(!self.args.no_ignore)
+            .git_exclude(!self.args.no_ignore)
+            .follow_links(self.args.follow);
+
+        builder.build_parallel().run(|| {
+            let this = self.clone();
+            Box::new(move |entry| {
+                match entry {
+                    Result::Ok(entry) => match entry.file_type() {
+                        Some(ft) if ft.is_file() => this.schedule_walk_path(entry.path()),
+                        // Directories are walked into, not skipped; only report genuinely
+                        // skipped entries (symlinks, devices, ...).
+                        Some(ft) if entry.depth() > 0 && !ft.is_dir() => {
+                            if this.args.verbose {
+                                this.progress.println(format!(
+                                    "INFO: skipping non-file {}",
+                                    entry.path().display()
+                                ));
+                            }
+                        }
+                        _ => {}
+                    },
+                    Err(err) => {
+                        if !this.args.quiet {
+                            this.progress.println(format!("WARN: {err}"));
+                        }
+                    }
+                }
+                WalkState::Continue
+            })
+        });
     }
 
+    /// Enqueue `path` onto the work queue for a worker thread to pick up.
     fn schedule_walk_path(self: &Arc<Self>, path: &std::path::Path) {
-        let path = path.to_path_buf();
-        let c = self.clone();
-        self.pool.execute(move || {
-            c.walk_path(&path)
-                .unwrap_or_else(|_| panic!("Failed to walk path {path:?}"));
-        });
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.progress.inc_length(1);
+        // workers only stop once run() has observed in_flight hit zero, so this can't fail
+        let _ = self.work_tx.send(WorkItem::Path(path.to_path_buf()));
     }
 
     fn walk_path(self: &Arc<Self>, path: &Path) -> Result<()> {
         // increment ops complete, before the work, so that a failure will not
-        self.ops_complete
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.ops_complete.fetch_add(1, Ordering::Relaxed);
 
         let result = {
             let this = &self;
             let path_str = path.to_str().unwrap();
-            if path.is_dir() {
-                this.walk_dir(path)
-            } else if path_str.ends_with(".zip") {
-                let mut file = fs::File::open(path)?;
-                this.walk_zip(path_str, &mut file)
+            let format = Format::detect(path_str);
+            if format == Format::Zip {
+                // Zip needs Seek to read its central directory; the top-level file is
+                // already a seekable fs::File, so open it as a zip archive directly instead
+                // of boxing it as `dyn Read` and buffering it into memory just to get Seek
+                // back.
+                let file = fs::File::open(path)?;
+                let budget = ExpansionBudget::new(this.args.max_archive_depth, this.args.max_expanded_bytes);
+                let archive = zip::ZipArchive::new(file)?;
+                this.walk_zip_entries(path_str, 0, &budget, archive)
+            } else if format.is_archive() {
+                let file = fs::File::open(path)?;
+                let budget = ExpansionBudget::new(this.args.max_archive_depth, this.args.max_expanded_bytes);
+                this.walk_archive(path_str, 0, &budget, format, Box::new(file))
             } else if path.is_file() {
                 this.progress.set_message(format!("processing: {path_str}"));
                 this.process_file(path_str, &File::open(path)?)?;
@@ -266,22 +446,108 @@ where
         Ok(())
     }
 
-    fn walk_zip(self: &Arc<Self>, path: &str, zip_file: &mut File) -> Result<()> {
-        let mut archive = zip::ZipArchive::new(zip_file)?;
-        for i in 0..archive.len() {
-            let zip_file = archive.by_index(i)?;
-            if zip_file.is_dir() {
-                // just a directory placeholder.
-            } else {
-                let file_name = path.to_string() + "!" + zip_file.name();
-                if file_name.ends_with(".zip") {
-                    self.progress
-                        .println(format!("No support for a zip of a zip yet {file_name}"));
+    /// Recursively descend into an archive/compressed stream, dispatching on `format`:
+    /// containers (`Zip`, `Tar`, `TarGzip`) are enumerated entry by entry, appending each
+    /// entry's name to the `!`-delimited virtual path; single-stream compressors (`Gzip`,
+    /// `Bzip2`, `Xz`, `Zstd`) are decoded and recursed into under their unsuffixed name;
+    /// anything else is handed to `search_file` as plain text. `budget` bounds both the
+    /// nesting depth and the total bytes expanded out of the originating top-level file.
+    fn walk_archive<'a>(
+        self: &Arc<Self>,
+        path: &str,
+        depth: usize,
+        budget: &ExpansionBudget,
+        format: Format,
+        data: Box<dyn Read + 'a>,
+    ) -> Result<()> {
+        budget.check_depth(depth)?;
+        match format {
+            Format::Zip => {
+                let mut buf = Vec::new();
+                archive::read_capped(data, budget, &mut buf)?;
+                let archive = zip::ZipArchive::new(Cursor::new(buf))?;
+                self.walk_zip_entries(path, depth, budget, archive)
+            }
+            Format::Tar | Format::TarGzip => {
+                let data: Box<dyn Read + 'a> = if format == Format::TarGzip {
+                    Box::new(flate2::read::GzDecoder::new(data))
                 } else {
-                    self.search_file(&file_name, zip_file)?;
+                    data
+                };
+                let mut archive = tar::Archive::new(data);
+                for entry in archive.entries()? {
+                    let entry = entry?;
+                    if !entry.header().entry_type().is_file() {
+                        continue;
+                    }
+                    let name = entry.path()?.to_string_lossy().into_owned();
+                    let entry_path = format!("{path}{}{name}", self.args.zip_delimiter);
+                    self.dispatch_entry(&entry_path, depth + 1, budget, entry)?;
                 }
+                Ok(())
+            }
+            Format::Gzip | Format::Bzip2 | Format::Xz | Format::Zstd => {
+                let inner_path = format.strip_suffix(path);
+                let decoded = format.decode_stream(data)?;
+                self.dispatch_entry(&inner_path, depth + 1, budget, decoded)
+            }
+            Format::Plain => self.search_file(path, data),
+        }
+    }
+
+    /// Enumerate an already-opened zip archive's entries, appending each entry's name to
+    /// the `!`-delimited virtual path. Split out of `walk_archive` so the top-level file
+    /// (already a seekable `fs::File`) can be opened as a zip directly, instead of being
+    /// boxed as `dyn Read` and buffered into memory just to get `Seek` back.
+    fn walk_zip_entries<R: Read + Seek>(
+        self: &Arc<Self>,
+        path: &str,
+        depth: usize,
+        budget: &ExpansionBudget,
+        mut archive: zip::ZipArchive<R>,
+    ) -> Result<()> {
+        budget.check_depth(depth)?;
+        for i in 0..archive.len() {
+            let entry = archive.by_index(i)?;
+            if entry.is_dir() {
+                continue;
             }
+            let entry_path = format!("{path}{}{}", self.args.zip_delimiter, entry.name());
+            self.dispatch_entry(&entry_path, depth + 1, budget, entry)?;
         }
         Ok(())
     }
+
+    /// Detect `path`'s format and continue the recursive archive walk. `Zip` is the only
+    /// format that needs `Seek` to read its central directory, so it's the only case that
+    /// has to be buffered into memory first; everything else keeps streaming straight from
+    /// `data`, metered against `budget` as it's read.
+    fn dispatch_entry<'a, R: Read + 'a>(
+        self: &Arc<Self>,
+        path: &str,
+        depth: usize,
+        budget: &ExpansionBudget,
+        data: R,
+    ) -> Result<()> {
+        let format = Format::detect(path);
+        if format == Format::Zip {
+            // walk_archive buffers a zip through read_capped, which charges every byte
+            // against `budget` once, so the entry isn't metered here as well.
+            self.walk_archive(path, depth, budget, format, Box::new(data))
+        } else {
+            let metered: Box<dyn Read + 'a> = Box::new(BudgetedReader::new(data, budget));
+            self.walk_archive(path, depth, budget, format, metered)
+        }
+    }
+}
+
+/// Best-effort extraction of a human-readable message from a caught panic payload.
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
 }
@@ -1,7 +1,12 @@
-use crate::{shared_iterator::SharedIterator, Args, Output, TextProcessor, ZipDirAnalyzer};
+use crate::{shared_iterator::SharedIterator, TextProcessor, ZipDirAnalyzer};
 use anyhow::{Ok, Result};
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
 use regex::Regex;
-use std::io::{BufRead, BufReader, Read};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Cursor, Read},
+};
 
 #[derive(Clone)]
 pub struct RegexProcessor {
@@ -17,12 +22,28 @@ impl RegexProcessor {
 }
 impl TextProcessor for ZipDirAnalyzer<RegexProcessor> {
     /// base file searching routine
-    fn process_file<T: Read>(&self, args: &Args, path: &str, data: T) -> Result<bool> {
+    fn process_file<T: Read>(&self, path: &str, data: T) -> Result<bool> {
         let mut consecutive_error_count = 0;
-        let mut lines = BufReader::new(data).lines();
+        let mut line_number: u64 = 0;
+
+        // Ring buffer of the last `before` successfully-read, non-matching lines, drained
+        // ahead of each match for -B context. `last_printed_line` tracks how far a prior
+        // match's context already printed, so overlapping before/after context isn't
+        // double-printed.
+        let mut before_buffer: VecDeque<(u64, String)> = VecDeque::with_capacity(self.args.before as usize);
+        let mut last_printed_line: u64 = 0;
+
+        // Sniff a BOM to pick UTF-16LE/BE/UTF-8, falling back to `--encoding` (default
+        // windows-1252) for files with no BOM, so non-UTF-8 text inside zips doesn't get
+        // skipped line-by-line as decode errors.
+        let default_encoding =
+            Encoding::for_label(self.args.encoding.as_bytes()).unwrap_or(encoding_rs::UTF_8);
+        let decoded = decode_with_bom_fallback(data, default_encoding)?;
+        let mut lines = BufReader::new(decoded).lines();
         let lines = SharedIterator::new(&mut lines);
 
         for line_result in lines.clone() {
+            line_number += 1;
             match line_result {
                 Err(err) => {
                     if consecutive_error_count > self.args.max_errors {
@@ -42,43 +63,34 @@ impl TextProcessor for ZipDirAnalyzer<RegexProcessor> {
                     consecutive_error_count += 1;
                 }
                 Result::Ok(line) => {
-                    // only process capture groups if needed
-                    if let Output::Capture = &args.output {
-                        if let Some(caps) = self.processor.regex.captures(&line) {
-                            // line matched, so now report
-                            let more_lines = &mut lines.clone().map(|r| r.unwrap());
-                            let this_line = core::iter::once(line.clone());
-                            let mut all_lines = this_line.chain(more_lines);
-                            let mut caps = caps
-                                .iter()
-                                .flat_map(|c| c.into_iter())
-                                .map(|c| c.as_str().to_string())
-                                .collect::<Vec<String>>();
-
-                            let capture_groups = &args.capture_groups;
-                            if !capture_groups.is_empty() {
-                                caps = capture_groups
-                                    .iter()
-                                    .map(|i| caps.get(*i).unwrap_or(&"".to_string()).clone())
-                                    .collect();
-                            }
-
-                            let capture_delimiter = &args.capture_delimiter;
-                            let regex = caps.join(capture_delimiter);
-                            if self.report(path, regex.as_str(), &mut all_lines)? {
-                                // only needed to match once, so exit early
-                                return Ok(true);
-                            }
-                        }
-                    } else if self.processor.regex.is_match(&line) {
+                    let mut matched = false;
+                    if self.processor.regex.is_match(&line) {
+                        matched = true;
                         // line matched, so now report
-                        let more_lines = &mut lines.clone().map(|r| r.unwrap());
+                        let before_lines = before_context(&before_buffer, last_printed_line);
+                        // Count the -A lines pulled off the shared iterator, so line_number
+                        // stays in step with the file after the lookahead.
+                        let after_count = std::cell::Cell::new(0u64);
+                        let more_lines = &mut lines
+                            .clone()
+                            .map(|r| r.unwrap())
+                            .take(self.args.after as usize)
+                            .inspect(|_| after_count.set(after_count.get() + 1));
                         let this_line = core::iter::once(line);
-                        let mut all_lines = this_line.chain(more_lines);
-                        if self.report(path, "", &mut all_lines)? {
+                        let mut all_lines = before_lines.into_iter().chain(this_line).chain(more_lines);
+                        if self.report(path, line_number, &mut all_lines)? {
                             // only needed to match once, so exit early
                             return Ok(true);
                         }
+                        line_number += after_count.get();
+                        last_printed_line = line_number;
+                    }
+
+                    if !matched {
+                        before_buffer.push_back((line_number, line));
+                        if before_buffer.len() > self.args.before as usize {
+                            before_buffer.pop_front();
+                        }
                     }
 
                     consecutive_error_count = 0;
@@ -88,3 +100,79 @@ impl TextProcessor for ZipDirAnalyzer<RegexProcessor> {
         Ok(false)
     }
 }
+
+/// Wrap `data` in a decoder that auto-detects a UTF-16LE/BE/UTF-8 BOM if present, only
+/// falling back to `fallback_encoding` when there's no BOM.
+/// `DecodeReaderBytesBuilder::encoding` disables BOM sniffing outright once set, so the BOM
+/// has to be peeked here first and only forced to `fallback_encoding` when none is found.
+fn decode_with_bom_fallback<T: Read>(
+    mut data: T,
+    fallback_encoding: &'static Encoding,
+) -> Result<impl Read> {
+    let mut bom_buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < bom_buf.len() {
+        let n = data.read(&mut bom_buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    let has_bom = Encoding::for_bom(&bom_buf[..filled]).is_some();
+    let prefixed = Cursor::new(bom_buf[..filled].to_vec()).chain(data);
+
+    let mut builder = DecodeReaderBytesBuilder::new();
+    builder.bom_sniffing(true);
+    if !has_bom {
+        builder.encoding(Some(fallback_encoding));
+    }
+    Ok(builder.build(prefixed))
+}
+
+/// Collect the buffered before-context lines that are newer than `last_printed_line`,
+/// oldest first, so context already printed by a previous overlapping match isn't repeated.
+fn before_context(buffer: &VecDeque<(u64, String)>, last_printed_line: u64) -> Vec<String> {
+    buffer
+        .iter()
+        .filter(|(line_number, _)| *line_number > last_printed_line)
+        .map(|(_, line)| line.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn before_context_excludes_already_printed_lines() {
+        let mut buffer = VecDeque::new();
+        buffer.push_back((1, "one".to_string()));
+        buffer.push_back((2, "two".to_string()));
+        buffer.push_back((3, "three".to_string()));
+
+        // Near the start of a file the ring buffer holds fewer than `--before` lines; all
+        // of them are still unprinted context.
+        assert_eq!(
+            vec!["one".to_string(), "two".to_string(), "three".to_string()],
+            before_context(&buffer, 0)
+        );
+
+        // A prior match already printed through line 2; only the rest is new context.
+        assert_eq!(vec!["three".to_string()], before_context(&buffer, 2));
+    }
+
+    #[test]
+    fn decode_with_bom_fallback_prefers_bom_over_fallback_encoding() {
+        let mut bytes = vec![0xFF, 0xFE]; // UTF-16LE BOM
+        for unit in "hi".encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+
+        // Forcing windows-1252 here would garble the UTF-16 bytes; the BOM must win.
+        let decoded =
+            decode_with_bom_fallback(Cursor::new(bytes), encoding_rs::WINDOWS_1252).unwrap();
+        let mut out = String::new();
+        BufReader::new(decoded).read_to_string(&mut out).unwrap();
+        assert_eq!("hi", out);
+    }
+}
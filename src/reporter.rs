@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use crossbeam_channel::{unbounded, RecvTimeoutError, Sender};
+use std::{
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How long `Auto` buffers results before giving up on sorting and streaming instead.
+/// Modeled on fd's `ReceiverMode`.
+const DEFAULT_MAX_BUFFER_TIME: Duration = Duration::from_millis(100);
+
+/// Once more than this many entries are buffered, `Auto` gives up on sorting and streams
+/// instead, even if `DEFAULT_MAX_BUFFER_TIME` hasn't elapsed yet.
+const MAX_BUFFER_LENGTH: usize = 1000;
+
+/// How the reporter orders output relative to when matches are found.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortMode {
+    /// Buffer briefly for deterministic, path-sorted output on small searches, then fall
+    /// back to unsorted streaming so huge trees stay responsive (the default).
+    #[default]
+    Auto,
+    /// Always buffer every result and print in sorted order, however long that takes.
+    Sort,
+    /// Never buffer; print each result as soon as it's found, unsorted.
+    NoSort,
+}
+
+/// One reported match, ordered by (path, line number) while buffering.
+struct Match {
+    path: String,
+    line_number: u64,
+    text: String,
+}
+
+/// Sort `buffer` by (path, line number) and return just the text, in that order.
+fn sorted_texts(mut buffer: Vec<Match>) -> Vec<String> {
+    buffer.sort_by(|a, b| (&a.path, a.line_number).cmp(&(&b.path, b.line_number)));
+    buffer.into_iter().map(|m| m.text).collect()
+}
+
+/// Routes match results from worker threads through a single channel to one printer
+/// thread, so stdout writes never interleave and, when buffering, come out sorted.
+pub struct Reporter {
+    sender: Sender<Match>,
+    handle: JoinHandle<()>,
+}
+
+impl Reporter {
+    pub fn spawn(mode: SortMode) -> Self {
+        let (sender, receiver) = unbounded();
+        let handle = thread::spawn(move || {
+            if mode == SortMode::NoSort {
+                for m in receiver {
+                    println!("{}", m.text);
+                }
+                return;
+            }
+
+            if mode == SortMode::Sort {
+                // Buffer every result, however long the search takes, for a fully sorted report.
+                flush_sorted(receiver.iter().collect());
+                return;
+            }
+
+            // Auto: collect results, sorted by (path, line number), until either
+            // DEFAULT_MAX_BUFFER_TIME or MAX_BUFFER_LENGTH is exceeded, then fall back to
+            // unsorted streaming for the remainder so huge trees stay responsive.
+            let mut buffer = Vec::new();
+            let deadline = Instant::now() + DEFAULT_MAX_BUFFER_TIME;
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match receiver.recv_timeout(timeout) {
+                    std::result::Result::Ok(m) => {
+                        buffer.push(m);
+                        if buffer.len() > MAX_BUFFER_LENGTH {
+                            flush_sorted_then_stream(buffer, &receiver);
+                            return;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        flush_sorted_then_stream(buffer, &receiver);
+                        return;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        flush_sorted(buffer);
+                        return;
+                    }
+                }
+            }
+        });
+        Self { sender, handle }
+    }
+
+    pub fn send(&self, path: &str, line_number: u64, text: String) {
+        // The printer thread only ever disconnects after we do, so this can't fail.
+        let _ = self.sender.send(Match {
+            path: path.to_string(),
+            line_number,
+            text,
+        });
+    }
+
+    /// Signal that no more results are coming and block until the printer thread has
+    /// flushed everything it buffered.
+    pub fn finish(self) -> Result<()> {
+        drop(self.sender);
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("reporter thread panicked"))
+    }
+}
+
+fn flush_sorted(buffer: Vec<Match>) {
+    for text in sorted_texts(buffer) {
+        println!("{text}");
+    }
+}
+
+fn flush_sorted_then_stream(buffer: Vec<Match>, receiver: &crossbeam_channel::Receiver<Match>) {
+    flush_sorted(buffer);
+    for m in receiver {
+        println!("{}", m.text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(path: &str, line_number: u64, text: &str) -> Match {
+        Match {
+            path: path.to_string(),
+            line_number,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn sorted_texts_orders_by_path_then_line_number() {
+        let buffer = vec![
+            m("b.txt", 1, "b1"),
+            m("a.txt", 2, "a2"),
+            m("a.txt", 1, "a1"),
+        ];
+        assert_eq!(vec!["a1", "a2", "b1"], sorted_texts(buffer));
+    }
+}